@@ -1,27 +1,36 @@
 use krilla::tagging as kt;
-use krilla::tagging::{LineHeight, NaiveRgbColor, Node, Tag, TextDecorationType};
-use typst_library::diag::{SourceResult, bail};
+use krilla::tagging::{
+    LineHeight, NaiveRgbColor, Node, Tag, TextDecorationStyle, TextDecorationType,
+};
 use typst_library::foundations::{Content, Smart};
 use typst_library::introspection::Location;
 use typst_library::layout::{Abs, Length};
 use typst_library::text::{Font, ScriptKind, TextItem, TextSize};
-use typst_library::visualize::{Paint, Stroke};
+use typst_library::visualize::{DashLength, DashPattern, Paint, Stroke};
 
-use crate::PdfOptions;
 use crate::tags::convert;
 use crate::util::AbsExt;
 
+/// Number of resolved attribute sets kept around before the least-recently-used
+/// one is evicted. Runs that alternate between more distinct font/size pairs
+/// than this will still thrash, but a handful covers the common cases (mixed
+/// scripts, inline math, differently-sized superscripts).
+const RESOLVE_CACHE_CAP: usize = 8;
+
 #[derive(Clone, Debug)]
 pub struct TextAttrs {
-    /// Store the last resolved set of text attribute. The resolution isn't that
-    /// expensive, but for large bodies of text it is resolved quite often.
-    last_resolved: Option<(TextParams, ResolvedTextAttrs)>,
+    /// A small LRU of resolved attribute sets, keyed by the text parameters that
+    /// affect resolution. A single slot would miss on every alternation in a
+    /// paragraph that interleaves two fonts or sizes under the same attribute
+    /// stack, re-walking the whole `items` stack each run. Invalidated wholesale
+    /// on `push`/`pop`, since those change the stack.
+    resolved: Vec<(TextParams, ResolvedTextAttrs)>,
     items: Vec<(Location, TextAttr)>,
 }
 
 impl TextAttrs {
     pub const fn new() -> Self {
-        Self { last_resolved: None, items: Vec::new() }
+        Self { resolved: Vec::new(), items: Vec::new() }
     }
 
     pub fn push_script(
@@ -42,44 +51,28 @@ impl TextAttrs {
 
     pub fn push_deco(
         &mut self,
-        options: &PdfOptions,
         elem: &Content,
         kind: TextDecoKind,
         stroke: Smart<Stroke>,
-    ) -> SourceResult<()> {
+        offset: Smart<Length>,
+    ) {
         let stroke = TextDecoStroke::from(stroke);
-        let deco = TextDeco { kind, stroke };
-
-        // TODO: can overlapping tags break this?
-        // PDF can only represent one text decoration style at a time.
-        // If PDF/UA-1 is enforced throw an error.
-        if options.is_pdf_ua()
-            && self
-                .items
-                .iter()
-                .filter_map(|(_, a)| a.as_deco())
-                .any(|d| d.kind != deco.kind)
-        {
-            let validator = options.standards.config.validator().as_str();
-            bail!(
-                elem.span(),
-                "{validator} error: cannot combine underline, overline, or strike"
-            );
-        }
-
+        let deco = TextDeco { kind, stroke, offset };
+        // Overlapping decorations of different kinds are composed as nested
+        // spans in `resolve_nodes` (a krilla `Span` carries a single decoration
+        // type), so there is nothing to reject here.
         self.push(elem, TextAttr::Deco(deco));
-        Ok(())
     }
 
     pub fn push(&mut self, elem: &Content, attr: TextAttr) {
         let loc = elem.location().unwrap();
-        self.last_resolved = None;
+        self.resolved.clear();
         self.items.push((loc, attr));
     }
 
     /// Returns true if a decoration was removed.
     pub fn pop(&mut self, loc: Location) -> bool {
-        self.last_resolved = None;
+        self.resolved.clear();
 
         // TODO: Ideally we would just check the top of the stack, can
         // overlapping tags even happen for decorations?
@@ -92,14 +85,18 @@ impl TextAttrs {
 
     pub fn resolve(&mut self, text: &TextItem) -> ResolvedTextAttrs {
         let params = TextParams::new(text);
-        if let Some((prev_params, attrs)) = &self.last_resolved
-            && prev_params == &params
-        {
-            return *attrs;
+        if let Some(i) = self.resolved.iter().position(|(p, _)| *p == params) {
+            // Promote the hit to the most-recently-used slot.
+            let entry = self.resolved.remove(i);
+            self.resolved.push(entry);
+            return self.resolved.last().unwrap().1;
         }
 
         let attrs = resolve_attrs(&self.items, &text.font, text.size);
-        self.last_resolved = Some((params, attrs));
+        if self.resolved.len() == RESOLVE_CACHE_CAP {
+            self.resolved.remove(0);
+        }
+        self.resolved.push((params, attrs));
         attrs
     }
 }
@@ -113,12 +110,6 @@ pub enum TextAttr {
     Deco(TextDeco),
 }
 
-impl TextAttr {
-    fn as_deco(&self) -> Option<&TextDeco> {
-        if let Self::Deco(v) = self { Some(v) } else { None }
-    }
-}
-
 /// Sub- or super-script.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Script {
@@ -131,6 +122,8 @@ pub struct Script {
 pub struct TextDeco {
     kind: TextDecoKind,
     stroke: TextDecoStroke,
+    /// Vertical offset of the decoration line, as authored on the element.
+    offset: Smart<Length>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -150,10 +143,31 @@ impl TextDecoKind {
     }
 }
 
+/// The visual style of a decoration line, mirroring the CSS
+/// `text-decoration-style` values that Typst's stroke settings can express.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum TextDecoStyle {
+    #[default]
+    Solid,
+    Dotted,
+    Dashed,
+}
+
+impl TextDecoStyle {
+    fn to_krilla(self) -> TextDecorationStyle {
+        match self {
+            TextDecoStyle::Solid => TextDecorationStyle::Solid,
+            TextDecoStyle::Dotted => TextDecorationStyle::Dotted,
+            TextDecoStyle::Dashed => TextDecorationStyle::Dashed,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 struct TextDecoStroke {
     color: Option<NaiveRgbColor>,
     thickness: Option<Length>,
+    style: TextDecoStyle,
 }
 
 impl TextDecoStroke {
@@ -163,8 +177,29 @@ impl TextDecoStroke {
         };
         let color = stroke.paint.custom().as_ref().and_then(convert::paint_to_color);
         let thickness = stroke.thickness.custom();
-        TextDecoStroke { color, thickness }
+        // `auto` or an explicit `none` dash draws a plain solid rule; otherwise
+        // classify the pattern into a dotted or dashed line.
+        let style = match stroke.dash.custom() {
+            Some(Some(pattern)) => deco_style(&pattern),
+            _ => TextDecoStyle::Solid,
+        };
+        TextDecoStroke { color, thickness, style }
+    }
+}
+
+/// Classify a dash pattern as dotted or dashed. A pattern whose drawn segments
+/// are only as long as the line is wide reads as dotted; anything longer is
+/// dashed.
+fn deco_style(pattern: &DashPattern<Length>) -> TextDecoStyle {
+    if pattern.array.is_empty() {
+        return TextDecoStyle::Solid;
     }
+    let dotted = pattern
+        .array
+        .iter()
+        .step_by(2)
+        .all(|seg| matches!(seg, DashLength::LineWidth));
+    if dotted { TextDecoStyle::Dotted } else { TextDecoStyle::Dashed }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -173,7 +208,7 @@ pub struct ResolvedTextAttrs {
     emph: Option<bool>,
     script: Option<ResolvedScript>,
     background: Option<Option<NaiveRgbColor>>,
-    deco: Option<ResolvedTextDeco>,
+    decos: ResolvedTextDecos,
 }
 
 impl ResolvedTextAttrs {
@@ -182,21 +217,13 @@ impl ResolvedTextAttrs {
         emph: None,
         script: None,
         background: None,
-        deco: None,
+        decos: ResolvedTextDecos::EMPTY,
     };
 
     pub fn is_empty(&self) -> bool {
         self == &Self::EMPTY
     }
 
-    pub fn all_resolved(&self) -> bool {
-        self.strong.is_some()
-            && self.emph.is_some()
-            && self.script.is_some()
-            && self.background.is_some()
-            && self.deco.is_some()
-    }
-
     pub fn resolve_nodes(self, accum: &mut Vec<Node>, children: Vec<kt::Identifier>) {
         enum Prev {
             Children(Vec<kt::Identifier>),
@@ -213,14 +240,26 @@ impl ResolvedTextAttrs {
         }
 
         let mut prev = Prev::Children(children);
-        if self.script.is_some() || self.background.is_some() || self.deco.is_some() {
+        if self.script.is_some() || self.background.is_some() {
             let tag = Tag::Span
                 .with_line_height(self.script.map(|s| s.lineheight))
                 .with_baseline_shift(self.script.map(|s| s.baseline_shift))
-                .with_background_color(self.background.flatten())
-                .with_text_decoration_type(self.deco.map(|d| d.kind.to_krilla()))
-                .with_text_decoration_color(self.deco.and_then(|d| d.color))
-                .with_text_decoration_thickness(self.deco.and_then(|d| d.thickness));
+                .with_background_color(self.background.flatten());
+
+            let group = kt::TagGroup::with_children(tag, prev.into_nodes());
+            prev = Prev::Group(group);
+        }
+        // A single krilla `Span` can only carry one decoration type, so emit one
+        // wrapping span per active decoration. Walking in reverse push order
+        // nests later decorations inside earlier ones (e.g. a line-through span
+        // inside an underline span) while keeping the order stable across items.
+        for deco in self.decos.iter().rev() {
+            let tag = Tag::Span
+                .with_text_decoration_type(Some(deco.kind.to_krilla()))
+                .with_text_decoration_color(deco.color)
+                .with_text_decoration_thickness(deco.thickness)
+                .with_text_decoration_style(Some(deco.style.to_krilla()))
+                .with_text_decoration_offset(deco.offset);
 
             let group = kt::TagGroup::with_children(tag, prev.into_nodes());
             prev = Prev::Group(group);
@@ -252,6 +291,38 @@ pub struct ResolvedTextDeco {
     kind: TextDecoKind,
     color: Option<NaiveRgbColor>,
     thickness: Option<f32>,
+    style: TextDecoStyle,
+    offset: Option<f32>,
+}
+
+/// The decorations active for a single text item, at most one per kind and
+/// ordered by when the outermost element of each kind was pushed. Keeping the order
+/// stable ensures adjacent items that share a decoration nest their spans the
+/// same way, so the krilla tree doesn't grow spurious sibling groups.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ResolvedTextDecos {
+    decos: [Option<ResolvedTextDeco>; 3],
+}
+
+impl ResolvedTextDecos {
+    const EMPTY: Self = Self { decos: [None; 3] };
+
+    /// Records a decoration, keeping the innermost one of each kind. Called in
+    /// push order; a kind that is already present keeps its slot, so the slots
+    /// stay sorted by when the first decoration of each kind opened.
+    fn insert(&mut self, deco: ResolvedTextDeco) {
+        let slot = self
+            .decos
+            .iter_mut()
+            .find(|slot| slot.is_none_or(|d| d.kind == deco.kind));
+        if let Some(slot) = slot {
+            *slot = Some(deco);
+        }
+    }
+
+    fn iter(&self) -> impl DoubleEndedIterator<Item = ResolvedTextDeco> {
+        self.decos.into_iter().flatten()
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -276,13 +347,15 @@ fn resolve_attrs(
     size: Abs,
 ) -> ResolvedTextAttrs {
     let mut attrs = ResolvedTextAttrs::EMPTY;
-    for (_, attr) in items.iter().rev() {
+    // Walk in push order so the innermost value of each scalar attribute wins
+    // and the decoration slots stay sorted by when each kind first opened.
+    for (_, attr) in items.iter() {
         match *attr {
             TextAttr::Strong => {
-                attrs.strong.get_or_insert(true);
+                attrs.strong = Some(true);
             }
             TextAttr::Emph => {
-                attrs.emph.get_or_insert(true);
+                attrs.emph = Some(true);
             }
             TextAttr::Script(script) => {
                 // TODO: The `typographic` setting is ignored for now.
@@ -298,25 +371,26 @@ fn resolve_attrs(
                 let lineheight = (script.lineheight.map(|s| s.0.at(size)))
                     .unwrap_or_else(|| script_metrics.height.at(size));
 
-                attrs.script.get_or_insert_with(|| ResolvedScript {
+                attrs.script = Some(ResolvedScript {
                     baseline_shift: baseline_shift.to_f32(),
                     lineheight: LineHeight::Custom(lineheight.to_f32()),
                 });
             }
             TextAttr::Highlight(color) => {
-                attrs.background.get_or_insert(color);
+                attrs.background = Some(color);
             }
-            TextAttr::Deco(TextDeco { kind, stroke }) => {
-                attrs.deco.get_or_insert_with(|| {
-                    let thickness = stroke.thickness.map(|t| t.at(size).to_f32());
-                    ResolvedTextDeco { kind, color: stroke.color, thickness }
+            TextAttr::Deco(TextDeco { kind, stroke, offset }) => {
+                let thickness = stroke.thickness.map(|t| t.at(size).to_f32());
+                let offset = offset.custom().map(|o| o.at(size).to_f32());
+                attrs.decos.insert(ResolvedTextDeco {
+                    kind,
+                    color: stroke.color,
+                    thickness,
+                    style: stroke.style,
+                    offset,
                 });
             }
         }
-
-        if attrs.all_resolved() {
-            break;
-        }
     }
     attrs
 }